@@ -1,47 +1,277 @@
 //! Utility module for fonts
 
 use crate::draw;
+use memmap2::Mmap;
 use rusttype::Font;
 use std::{
-    cell::RefCell, collections::HashMap, fs::File, hash, io::Read, mem, path::Path, rc::Rc, thread,
+    cell::RefCell, collections::HashMap, fmt, fs::File, hash, io::Read, mem, path::Path, rc::Rc,
+    thread,
 };
+use unicode_segmentation::UnicodeSegmentation;
 
 /// FontRef is used to store Fonts on widgets.
 pub type FontRef<'a> = &'a rusttype::Font<'a>;
 
+/// Everything that can go wrong resolving, loading or looking up a font, so a
+/// single misconfigured font name is reported to the caller as an error
+/// instead of aborting the whole dashboard.
+#[derive(Debug)]
+pub enum FontError {
+    /// fontconfig returned no candidate face for this family/style. (A
+    /// candidate file that fails to load is reported as `ParseFailure`.)
+    NotFound {
+        family: &'static str,
+        weight: Weight,
+        slant: Slant,
+    },
+    /// The `fontconfig` feature is disabled, so no font search is possible.
+    FontconfigUnavailable,
+    /// A font file couldn't be opened, read or parsed as a font.
+    ParseFailure { path: String },
+    /// `get_font` was asked for a size that was never queued (and so never
+    /// loaded), or `get_font_for_cluster` for a font whose fallback chain
+    /// was never loaded.
+    NoSuchSize {
+        family: &'static str,
+        weight: Weight,
+        slant: Slant,
+        size: f32,
+    },
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FontError::NotFound {
+                family,
+                weight,
+                slant,
+            } => write!(
+                f,
+                "no usable font found for {} (weight={:?}, slant={:?})",
+                family, weight, slant
+            ),
+            FontError::FontconfigUnavailable => {
+                write!(f, "fontconfig not enabled so font search not available")
+            }
+            FontError::ParseFailure { path } => {
+                write!(f, "failed to parse font file at {}", path)
+            }
+            FontError::NoSuchSize {
+                family,
+                weight,
+                slant,
+                size,
+            } => write!(
+                f,
+                "no font loaded for {} (weight={:?}, slant={:?}) at size {}",
+                family, weight, slant, size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FontError {}
+
+/// A font weight, on the usual 100 (thinnest) - 900 (heaviest) CSS/OpenType
+/// scale. Named constants are provided for the common cuts; anything in
+/// between is passed through to fontconfig as-is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Weight(pub u16);
+
+impl Weight {
+    pub const THIN: Weight = Weight(100);
+    pub const LIGHT: Weight = Weight(300);
+    pub const REGULAR: Weight = Weight(400);
+    pub const MEDIUM: Weight = Weight(500);
+    pub const BOLD: Weight = Weight(700);
+    pub const BLACK: Weight = Weight(900);
+}
+
+impl Default for Weight {
+    fn default() -> Self {
+        Weight::REGULAR
+    }
+}
+
+/// A font slant.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Slant {
+    Normal,
+    Italic,
+    Oblique,
+}
+
+impl Default for Slant {
+    fn default() -> Self {
+        Slant::Normal
+    }
+}
+
+impl Slant {
+    fn fc_value(self) -> &'static str {
+        match self {
+            Slant::Normal => "roman",
+            Slant::Italic => "italic",
+            Slant::Oblique => "oblique",
+        }
+    }
+}
+
+/// Builds the fontconfig pattern string for `name` at `weight`/`slant`, e.g.
+/// `"Inter:weight=700:slant=italic"`.
+fn fc_pattern(name: &str, weight: Weight, slant: Slant) -> String {
+    format!("{}:weight={}:slant={}", name, weight.0, slant.fc_value())
+}
+
+/// Resolves `name` at `weight`/`slant` to an ordered list of candidate font
+/// file paths, using fontconfig's sort mode (the equivalent of
+/// `fc-match -s "name:weight=...:slant=..."`). The first entry is
+/// fontconfig's best match; the rest are fallback faces fontconfig considers
+/// acceptable substitutes, in preference order. `build_chain` loads the whole
+/// list into a font's fallback chain, including for fonts that never end up
+/// needing a fallback (lookups then simply never go past the first face).
 #[cfg(feature = "fontconfig")]
-pub(crate) fn find_font(name: &str) -> String {
-    use fontconfig::Fontconfig as FontConfig;
-    let fc = FontConfig::new().unwrap();
-    fc.find(name, None)
-        .unwrap()
-        .path
-        .to_str()
-        .unwrap()
-        .to_string()
+pub(crate) fn find_font_fallbacks(
+    name: &'static str,
+    weight: Weight,
+    slant: Slant,
+) -> Result<Vec<String>, FontError> {
+    use fontconfig_sys as fc;
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+    use std::ptr;
+
+    let query = std::ffi::CString::new(fc_pattern(name, weight, slant)).map_err(|_| {
+        FontError::NotFound {
+            family: name,
+            weight,
+            slant,
+        }
+    })?;
+
+    let paths = unsafe {
+        let config = fc::FcConfigGetCurrent();
+        let pattern = fc::FcNameParse(query.as_ptr() as *const c_char);
+        fc::FcConfigSubstitute(config, pattern, fc::FcMatchKind::FcMatchPattern);
+        fc::FcDefaultSubstitute(pattern);
+
+        let mut result = fc::FcResultNoMatch;
+        let set = fc::FcFontSort(config, pattern, 1, ptr::null_mut(), &mut result);
+
+        let mut paths = vec![];
+        if !set.is_null() {
+            let fonts = std::slice::from_raw_parts((*set).fonts, (*set).nfont as usize);
+            for font in fonts {
+                let mut path_ptr: *mut c_char = ptr::null_mut();
+                if fc::FcPatternGetString(*font, fc::FC_FILE.as_ptr() as *const c_char, 0, &mut path_ptr)
+                    == fc::FcResultMatch
+                {
+                    if let Ok(path) = CStr::from_ptr(path_ptr).to_str() {
+                        paths.push(path.to_string());
+                    }
+                }
+            }
+            fc::FcFontSetDestroy(set);
+        }
+        fc::FcPatternDestroy(pattern);
+
+        paths
+    };
+
+    if paths.is_empty() {
+        return Err(FontError::NotFound {
+            family: name,
+            weight,
+            slant,
+        });
+    }
+    Ok(paths)
 }
 
 #[cfg(not(feature = "fontconfig"))]
-pub(crate) fn find_font(_name: &str) -> String {
-    panic!("fontconfig not enabled so font search not available");
+pub(crate) fn find_font_fallbacks(
+    _name: &'static str,
+    _weight: Weight,
+    _slant: Slant,
+) -> Result<Vec<String>, FontError> {
+    Err(FontError::FontconfigUnavailable)
+}
+
+/// Backing storage for a loaded font face: a memory-mapped file in the
+/// common case, or a fully-read owned buffer on filesystems/platforms where
+/// mapping isn't available.
+enum FaceBacking {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl std::ops::Deref for FaceBacking {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FaceBacking::Mapped(mmap) => &mmap[..],
+            FaceBacking::Owned(data) => &data[..],
+        }
+    }
+}
+
+/// A loaded font face together with the storage its bytes live in. Cached
+/// once per resolved path in `FontMap::face_cache` and shared (via `Rc`)
+/// across every `(name, size)` combination that resolves to the same file,
+/// so a given font file is only ever mapped (or read) once.
+pub(crate) struct FaceCacheEntry {
+    _backing: FaceBacking,
+    font: Font<'static>,
 }
 
 /// FontLoader is a marker struct that is used to load files
 pub(crate) struct FontLoader;
 
 impl FontLoader {
-    /// Given a path, loads it as a Font, which can be rendered to the screen.
-    pub(crate) fn from_path<'a, P>(path: P) -> Option<Font<'a>>
+    /// Given a path, memory-maps it and parses it as a font backed directly
+    /// by the mapped bytes, avoiding a full read-to-heap of the file. Falls
+    /// back to reading the file into an owned buffer if mapping it fails.
+    pub(crate) fn from_path<P>(path: P) -> Result<FaceCacheEntry, FontError>
     where
         P: AsRef<Path>,
     {
-        let mut file = File::open(path).expect("Font file not found");
-        let mut data = match file.metadata() {
-            Ok(metadata) => Vec::with_capacity(metadata.len() as usize),
-            Err(_) => vec![],
+        let path_string = || path.as_ref().to_string_lossy().into_owned();
+        let file = File::open(path.as_ref()).map_err(|_| FontError::ParseFailure {
+            path: path_string(),
+        })?;
+        let backing = match unsafe { Mmap::map(&file) } {
+            Ok(mmap) => FaceBacking::Mapped(mmap),
+            Err(_) => {
+                let mut file = file;
+                let mut data = match file.metadata() {
+                    Ok(metadata) => Vec::with_capacity(metadata.len() as usize),
+                    Err(_) => vec![],
+                };
+                file.read_to_end(&mut data)
+                    .map_err(|_| FontError::ParseFailure {
+                        path: path_string(),
+                    })?;
+                FaceBacking::Owned(data)
+            }
         };
-        file.read_to_end(&mut data).unwrap();
-        Font::try_from_vec(data)
+
+        // SAFETY: `font` borrows from `backing`, and both are bundled into
+        // the same `FaceCacheEntry` that outlives every reference to `font`
+        // handed out elsewhere (entries are cached for the process
+        // lifetime and never removed), so treating the borrow as 'static is
+        // sound as long as callers only ever reach `font` through an entry
+        // that's still alive.
+        let bytes: &'static [u8] = unsafe { mem::transmute(&backing[..]) };
+        let font = Font::try_from_bytes(bytes).ok_or_else(|| FontError::ParseFailure {
+            path: path_string(),
+        })?;
+
+        Ok(FaceCacheEntry {
+            _backing: backing,
+            font,
+        })
     }
 }
 
@@ -70,69 +300,278 @@ impl PartialEq for ComparableF32 {
 
 impl Eq for ComparableF32 {}
 
+/// The index of a face within a font's fallback chain. `0` is always the
+/// primary (requested) face; anything higher is a fallback fontconfig
+/// substituted in, in preference order. The last index in a chain is the
+/// terminal fallback: lookups that miss every other face are pinned to it so
+/// a face is always returned, even if it renders `.notdef`.
+type FaceIndex = usize;
+
+/// Identifies a requested font: family name plus the style selector
+/// (weight/slant) that distinguishes e.g. `("Inter", Bold, Normal)` from
+/// `("Inter", Regular, Normal)` as separate cached faces.
+type FontKey = (&'static str, Weight, Slant);
+
 pub struct FontMap {
-    fonts: HashMap<(&'static str, ComparableF32), draw::Font<'static>>,
-    font_paths: HashMap<&'static str, String>,
-    required_fonts: HashMap<&'static str, (&'static str, Vec<(f32, &'static str)>)>,
+    fonts: HashMap<(FontKey, FaceIndex, ComparableF32), draw::Font<'static>>,
+    font_paths: HashMap<FontKey, String>,
+    required_fonts: HashMap<FontKey, Vec<(f32, &'static str)>>,
+    /// Resolved fallback chain for each requested font, primary face first.
+    /// Populated by `load_fonts`.
+    font_chains: HashMap<FontKey, Vec<Rc<FaceCacheEntry>>>,
+    /// Loaded faces keyed by resolved file path, so a file shared by
+    /// several font keys (e.g. the same emoji fallback face pulled in by
+    /// multiple families, or one family at several sizes) is only mapped
+    /// once.
+    face_cache: HashMap<String, Rc<FaceCacheEntry>>,
+    /// Memoizes which face in a font's chain actually covers a given
+    /// codepoint, so repeated lookups (redraw-heavy widgets like a clock)
+    /// don't have to walk the chain and query charmaps every time.
+    glyph_face_cache: HashMap<(FontKey, char), FaceIndex>,
+    /// Per-face glyph cache capacity, passed to every `draw::Font` this map
+    /// creates. Bounds memory use for long-running sessions (a clock
+    /// ticking through every digit, scrolling workspace names) where glyphs
+    /// are rasterized lazily rather than all declared up front.
+    glyph_cache_capacity: usize,
 }
 
+/// A glyph cache capacity to pass to `FontMap::new` when callers don't have
+/// a more specific number in mind.
+pub const DEFAULT_GLYPH_CACHE_CAPACITY: usize = 256;
+
 impl FontMap {
-    pub fn new() -> FontMap {
+    pub fn new(glyph_cache_capacity: usize) -> FontMap {
         FontMap {
             fonts: HashMap::new(),
             font_paths: HashMap::new(),
             required_fonts: HashMap::new(),
+            font_chains: HashMap::new(),
+            face_cache: HashMap::new(),
+            glyph_face_cache: HashMap::new(),
+            glyph_cache_capacity,
         }
     }
 
-    pub fn queue_font(&mut self, font_name: &'static str, size: f32, preload: &'static str) {
-        match self.required_fonts.get_mut(font_name) {
-            Some(v) => v.1.push((size, preload)),
-            None => {
-                self.required_fonts
-                    .insert(font_name, (font_name, vec![(size, preload)]));
-            }
+    pub fn queue_font(
+        &mut self,
+        font_name: &'static str,
+        size: f32,
+        weight: Weight,
+        slant: Slant,
+        preload: &'static str,
+    ) {
+        self.required_fonts
+            .entry((font_name, weight, slant))
+            .or_insert_with(Vec::new)
+            .push((size, preload));
+    }
+
+    pub fn add_font_path(
+        &mut self,
+        font_name: &'static str,
+        weight: Weight,
+        slant: Slant,
+        font_path: String,
+    ) {
+        self.font_paths.insert((font_name, weight, slant), font_path);
+    }
+
+    /// Loads (or returns the cached) face for `path`, sharing the backing
+    /// mmap with every other font key that resolves to the same file.
+    fn load_face(&mut self, path: String) -> Result<Rc<FaceCacheEntry>, FontError> {
+        if let Some(entry) = self.face_cache.get(&path) {
+            return Ok(entry.clone());
         }
+        let entry = Rc::new(FontLoader::from_path(&path)?);
+        self.face_cache.insert(path, entry.clone());
+        Ok(entry)
     }
 
-    pub fn add_font_path(&mut self, font_name: &'static str, font_path: String) {
-        self.font_paths.insert(font_name, font_path);
+    /// Resolves the fallback chain for `key`: the explicit path from
+    /// `add_font_path` if one was given, otherwise fontconfig's sorted
+    /// match list, with each path loaded (or reused) via `load_face`. Faces
+    /// that fail to load are skipped rather than failing the whole chain, as
+    /// long as at least one face in the chain loads successfully.
+    fn build_chain(&mut self, key: FontKey) -> Result<Vec<Rc<FaceCacheEntry>>, FontError> {
+        let (font_name, weight, slant) = key;
+        let paths = match self.font_paths.get(&key) {
+            Some(path) => vec![path.clone()],
+            None => {
+                let paths = find_font_fallbacks(font_name, weight, slant)?;
+                self.font_paths.insert(key, paths[0].clone());
+                paths
+            }
+        };
+        let mut chain = vec![];
+        let mut last_error = None;
+        for path in paths {
+            match self.load_face(path) {
+                Ok(face) => chain.push(face),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        if chain.is_empty() {
+            // Prefer the specific failure (e.g. a parse error) a candidate
+            // path hit over the generic "nothing found" - it's the more
+            // useful thing to log.
+            return Err(last_error.unwrap_or(FontError::NotFound {
+                family: font_name,
+                weight,
+                slant,
+            }));
+        }
+        Ok(chain)
     }
 
-    pub fn load_fonts(&mut self) {
-        for (font_name, v) in self.required_fonts.iter() {
-            let path = match self.font_paths.get(font_name) {
-                Some(res) => res,
-                _ => {
-                    let s = find_font(font_name);
-                    self.font_paths.insert(font_name, s);
-                    self.font_paths.get(font_name).unwrap()
+    /// Loads every queued font. A font that fails to resolve is skipped -
+    /// its glyphs simply won't be available, rather than every other queued
+    /// font (which may well have resolved fine) being dropped along with it
+    /// - with every such failure collected and returned so the caller can
+    /// log them and substitute a default face for the affected widgets.
+    /// `self` still ends up with every font that *did* resolve usable,
+    /// regardless of how many others failed.
+    pub fn load_fonts(&mut self) -> Vec<FontError> {
+        let keys: Vec<FontKey> = self.required_fonts.keys().copied().collect();
+        let mut errors = vec![];
+        for key in keys {
+            if !self.font_chains.contains_key(&key) {
+                match self.build_chain(key) {
+                    Ok(chain) => {
+                        self.font_chains.insert(key, chain);
+                    }
+                    Err(e) => {
+                        errors.push(e);
+                        continue;
+                    }
                 }
-            };
-            let fontref = Box::leak(Box::new(
-                FontLoader::from_path(path).expect("unable to load font"),
-            ));
-            for (size, preload) in &v.1 {
-                if let Some(font) = self.fonts.get_mut(&(font_name, ComparableF32(*size))) {
-                    font.add_str_to_cache(preload);
-                } else {
-                    let mut font = draw::Font::new(fontref, *size);
-                    font.add_str_to_cache(preload);
-                    self.fonts.insert((font_name, ComparableF32(*size)), font);
+            }
+
+            let sizes = self.required_fonts.get(&key).unwrap().clone();
+            for (size, preload) in sizes {
+                // The primary face always gets a `draw::Font` at this size,
+                // whether or not `preload` is empty or every preloaded
+                // cluster happens to resolve to a fallback face - `get_font`
+                // (and lazy draw-time lookups that miss the warm set) expect
+                // it to already exist rather than being created on whatever
+                // glyph happens to be preloaded first.
+                let primary_fontref: &'static Font<'static> =
+                    unsafe { mem::transmute(&self.font_chains.get(&key).unwrap()[0].font) };
+                let capacity = self.glyph_cache_capacity;
+                self.fonts
+                    .entry((key, 0, ComparableF32(size)))
+                    .or_insert_with(|| draw::Font::new(primary_fontref, size, capacity));
+
+                for cluster in preload.graphemes(true) {
+                    let idx = {
+                        let chain = self.font_chains.get(&key).unwrap();
+                        Self::resolve_face(chain, &mut self.glyph_face_cache, key, cluster)
+                    };
+                    let fontref: &'static Font<'static> =
+                        unsafe { mem::transmute(&self.font_chains.get(&key).unwrap()[idx].font) };
+                    let cache_key = (key, idx, ComparableF32(size));
+                    let capacity = self.glyph_cache_capacity;
+                    // `add_str_to_cache` here only warms the glyph cache for
+                    // the preload string; any other glyph is rasterized
+                    // lazily on first draw (see `get_font_for_cluster`).
+                    self.fonts
+                        .entry(cache_key)
+                        .or_insert_with(|| draw::Font::new(fontref, size, capacity))
+                        .add_str_to_cache(cluster);
                 }
             }
         }
+        errors
+    }
+
+    /// Picks the first face in `chain` whose charmap covers the cluster's
+    /// base character, falling back to the last (terminal) face in the
+    /// chain on a miss. A base character plus any combining marks in the
+    /// same cluster are resolved together so a cluster never splits across
+    /// two faces.
+    fn resolve_face(
+        chain: &[Rc<FaceCacheEntry>],
+        cache: &mut HashMap<(FontKey, char), FaceIndex>,
+        key: FontKey,
+        cluster: &str,
+    ) -> FaceIndex {
+        let base = cluster
+            .chars()
+            .next()
+            .expect("grapheme cluster cannot be empty");
+
+        if let Some(&idx) = cache.get(&(key, base)) {
+            return idx;
+        }
+
+        let idx = chain
+            .iter()
+            .position(|face| face.font.glyph(base).id().0 != 0)
+            .unwrap_or(chain.len() - 1);
+        cache.insert((key, base), idx);
+        idx
+    }
+
+    /// Returns the face in the given font's fallback chain that should be
+    /// used to render `cluster`, loading and caching that face's
+    /// `draw::Font` at `size` on first use.
+    pub fn get_font_for_cluster(
+        &mut self,
+        font_name: &'static str,
+        size: f32,
+        weight: Weight,
+        slant: Slant,
+        cluster: &str,
+    ) -> Result<&mut draw::Font<'static>, FontError> {
+        let key = (font_name, weight, slant);
+        let idx = {
+            let chain = self.font_chains.get(&key).ok_or(FontError::NoSuchSize {
+                family: font_name,
+                weight,
+                slant,
+                size,
+            })?;
+            Self::resolve_face(chain, &mut self.glyph_face_cache, key, cluster)
+        };
+        let fontref: &'static Font<'static> =
+            unsafe { mem::transmute(&self.font_chains.get(&key).unwrap()[idx].font) };
+        let capacity = self.glyph_cache_capacity;
+
+        // The returned `draw::Font` rasterizes and caches `cluster`'s glyph
+        // lazily on first draw if it wasn't already warmed by a preload, so
+        // callers don't need to have enumerated it up front.
+        Ok(self
+            .fonts
+            .entry((key, idx, ComparableF32(size)))
+            .or_insert_with(|| draw::Font::new(fontref, size, capacity)))
     }
 
-    pub fn get_font(&mut self, font_name: &'static str, size: f32) -> &mut draw::Font<'static> {
+    /// Returns the *primary* face (fallback chain index `0`) for the given
+    /// font at `size`. This never consults the fallback chain, so text
+    /// containing a character the primary face doesn't cover will be
+    /// rendered with a missing glyph rather than silently picking up a
+    /// fallback face - callers rendering arbitrary text should use
+    /// `get_font_for_cluster` instead, which resolves each grapheme cluster
+    /// to whichever face in the chain actually covers it.
+    pub fn get_font(
+        &mut self,
+        font_name: &'static str,
+        size: f32,
+        weight: Weight,
+        slant: Slant,
+    ) -> Result<&mut draw::Font<'static>, FontError> {
         self.fonts
-            .get_mut(&(font_name, ComparableF32(size)))
-            .expect("no font at specified size")
+            .get_mut(&((font_name, weight, slant), 0, ComparableF32(size)))
+            .ok_or(FontError::NoSuchSize {
+                family: font_name,
+                weight,
+                slant,
+                size,
+            })
     }
 }
 
 pub enum MaybeFontMap {
-    Waiting(thread::JoinHandle<FontMap>),
+    Waiting(thread::JoinHandle<(FontMap, Vec<FontError>)>),
     Ready(Rc<RefCell<FontMap>>),
     Invalid,
 }
@@ -145,12 +584,24 @@ impl MaybeFontMap {
         }
     }
 
+    /// Joins the loading thread if the map is still waiting, blocking until
+    /// that thread finishes. A font that failed to
+    /// load doesn't stop the rest of the map from becoming `Ready` - it's
+    /// logged as a warning and `load_fonts`' partial result (every font that
+    /// *did* resolve) is kept, rather than one bad font name taking down the
+    /// whole dashboard.
     pub fn resolve(&mut self) {
         if matches!(self, MaybeFontMap::Waiting(_)) {
             let s = mem::replace(self, MaybeFontMap::Invalid);
             match s {
                 MaybeFontMap::Waiting(handle) => {
-                    *self = MaybeFontMap::Ready(Rc::new(RefCell::new(handle.join().unwrap())));
+                    let (map, errors) = handle
+                        .join()
+                        .expect("font loading thread panicked unexpectedly");
+                    for error in &errors {
+                        eprintln!("wldash: font loading warning: {}", error);
+                    }
+                    *self = MaybeFontMap::Ready(Rc::new(RefCell::new(map)));
                 }
                 _ => unreachable!(),
             }